@@ -0,0 +1,203 @@
+/*
+ * ECIES (Elliptic Curve Integrated Encryption Scheme), used to hide the
+ * plaintext body of a HANDSHAKE packet (the AUTH/PAD/NOW/TARGET framing
+ * built by `protocol::protocol`) from on-path observers, instead of only
+ * authenticating it. Follows the same encrypt-then-MAC shape as
+ * openethereum's `ecies::encrypt`/`decrypt`.
+ *
+ * Wire layout of an encrypted packet:
+ *
+ * > [2-byte big-endian length of everything below]
+ * > [32-byte client ephemeral x25519 public key]
+ * > [16-byte AES-128-CTR IV]
+ * > [ciphertext, same length as the plaintext]
+ * > [32-byte HMAC_SHA256(iv || ciphertext)]
+ *
+ * The ephemeral key is fresh per handshake, so the shared secret (and
+ * therefore the derived encryption/MAC keys) never repeats even if the
+ * same server public key is targeted twice.
+ */
+use errors::*;
+use aes_ctr::Aes128Ctr;
+use aes_ctr::stream_cipher::{NewStreamCipher, StreamCipherCore};
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const IV_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+const PUBKEY_LEN: usize = 32;
+const ENC_KEY_LEN: usize = 16;
+const MAC_KEY_LEN: usize = 16;
+
+/* SHA-256-based KDF: derive `ENC_KEY_LEN + MAC_KEY_LEN` bytes from the
+ * ECDH shared secret, in the style of the NIST SP 800-56A
+ * concatenation KDF with a single round (our output fits in one
+ * SHA-256 block). */
+fn kdf(shared_secret: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(&[0u8, 0u8, 0u8, 1u8]);
+    hasher.input(shared_secret);
+    hasher.result().to_vec()
+}
+
+fn derive_keys(shared_secret: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let okm = kdf(shared_secret);
+    (okm[..ENC_KEY_LEN].to_vec(), okm[ENC_KEY_LEN..ENC_KEY_LEN + MAC_KEY_LEN].to_vec())
+}
+
+fn hmac_tag(mac_key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    Hmac::<Sha256>::new_varkey(mac_key)
+        .map_err(|_| "ECIES HMAC key setup failed".into())
+        .map(|mut mac| {
+            mac.input(iv);
+            mac.input(ciphertext);
+            mac.result().code().as_slice().to_vec()
+        })
+}
+
+/* Encrypt `plaintext` to `server_public` using a fresh ephemeral
+ * x25519 keypair. Returns the fully framed packet, ready to write to
+ * the wire. */
+pub fn encrypt(server_public: &PublicKey, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let ephemeral_secret = EphemeralSecret::new(&mut OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral_secret);
+    let shared_secret = ephemeral_secret.diffie_hellman(server_public);
+
+    let (enc_key, mac_key) = derive_keys(shared_secret.as_bytes());
+
+    let mut iv = [0u8; IV_LEN];
+    rand::Rng::fill(&mut rand::thread_rng(), &mut iv);
+
+    let mut ciphertext = plaintext.to_vec();
+    Aes128Ctr::new_var(&enc_key, &iv)
+        .map_err(|_| "ECIES cipher setup failed".into())
+        .map(|mut cipher| cipher.apply_keystream(&mut ciphertext))?;
+
+    let tag = hmac_tag(&mac_key, &iv, &ciphertext)?;
+
+    let mut body = Vec::with_capacity(PUBKEY_LEN + IV_LEN + ciphertext.len() + MAC_LEN);
+    body.extend_from_slice(ephemeral_public.as_bytes());
+    body.extend_from_slice(&iv);
+    body.extend_from_slice(&ciphertext);
+    body.extend_from_slice(&tag);
+
+    if body.len() > u16::max_value() as usize {
+        return Err("ECIES packet too large to frame".into());
+    }
+    let len = body.len() as u16;
+
+    let mut framed = Vec::with_capacity(2 + body.len());
+    framed.push((len >> 8) as u8);
+    framed.push((len & 0xff) as u8);
+    framed.extend_from_slice(&body);
+    Ok(framed)
+}
+
+/* Decrypt a packet produced by `encrypt` using the server's static
+ * x25519 secret. */
+pub fn decrypt(server_secret: &x25519_dalek::StaticSecret, framed: &[u8]) -> Result<Vec<u8>> {
+    if framed.len() < 2 {
+        return Err("ECIES packet too short".into());
+    }
+    let declared_len = ((framed[0] as usize) << 8) | (framed[1] as usize);
+    let body = &framed[2..];
+    if body.len() < declared_len {
+        return Err("Incomplete ECIES packet".into());
+    }
+    let body = &body[..declared_len];
+
+    if body.len() < PUBKEY_LEN + IV_LEN + MAC_LEN {
+        return Err("ECIES packet too short".into());
+    }
+
+    let ephemeral_public_bytes: [u8; PUBKEY_LEN] = {
+        let mut buf = [0u8; PUBKEY_LEN];
+        buf.copy_from_slice(&body[..PUBKEY_LEN]);
+        buf
+    };
+    let ephemeral_public = PublicKey::from(ephemeral_public_bytes);
+
+    let iv = &body[PUBKEY_LEN..PUBKEY_LEN + IV_LEN];
+    let ciphertext = &body[PUBKEY_LEN + IV_LEN..body.len() - MAC_LEN];
+    let tag = &body[body.len() - MAC_LEN..];
+
+    let shared_secret = server_secret.diffie_hellman(&ephemeral_public);
+    let (enc_key, mac_key) = derive_keys(shared_secret.as_bytes());
+
+    let expected_tag = hmac_tag(&mac_key, iv, ciphertext)?;
+    if expected_tag.as_slice().ct_eq(tag).unwrap_u8() != 1 {
+        return Err("ECIES authentication failed".into());
+    }
+
+    let mut plaintext = ciphertext.to_vec();
+    Aes128Ctr::new_var(&enc_key, iv)
+        .map_err(|_| "ECIES cipher setup failed".into())
+        .map(|mut cipher| cipher.apply_keystream(&mut plaintext))?;
+
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    fn server_keypair() -> (StaticSecret, PublicKey) {
+        let secret = StaticSecret::new(&mut OsRng);
+        let public = PublicKey::from(&secret);
+        (secret, public)
+    }
+
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        let (server_secret, server_public) = server_keypair();
+        let packet = encrypt(&server_public, b"NOW 1517476212983\nTARGET 192.168.1.1:443").unwrap();
+        let plaintext = decrypt(&server_secret, &packet).unwrap();
+        assert_eq!(b"NOW 1517476212983\nTARGET 192.168.1.1:443".to_vec(), plaintext);
+    }
+
+    #[test]
+    fn decrypt_rejects_tampered_ciphertext() {
+        let (server_secret, server_public) = server_keypair();
+        let mut packet = encrypt(&server_public, b"some handshake body").unwrap();
+        let ciphertext_start = 2 + PUBKEY_LEN + IV_LEN;
+        packet[ciphertext_start] ^= 0xff;
+        assert!(decrypt(&server_secret, &packet).is_err());
+    }
+
+    #[test]
+    fn decrypt_rejects_tampered_tag() {
+        let (server_secret, server_public) = server_keypair();
+        let mut packet = encrypt(&server_public, b"some handshake body").unwrap();
+        let tag_start = packet.len() - MAC_LEN;
+        packet[tag_start] ^= 0xff;
+        assert!(decrypt(&server_secret, &packet).is_err());
+    }
+
+    #[test]
+    fn decrypt_rejects_tampered_ephemeral_key() {
+        let (server_secret, server_public) = server_keypair();
+        let mut packet = encrypt(&server_public, b"some handshake body").unwrap();
+        packet[2] ^= 0xff;
+        assert!(decrypt(&server_secret, &packet).is_err());
+    }
+
+    #[test]
+    fn decrypt_rejects_truncated_packet() {
+        let (server_secret, server_public) = server_keypair();
+        let packet = encrypt(&server_public, b"some handshake body").unwrap();
+        let truncated = &packet[..packet.len() - 10];
+        assert!(decrypt(&server_secret, truncated).is_err());
+    }
+
+    #[test]
+    fn decrypt_rejects_wrong_server_secret() {
+        let (_, server_public) = server_keypair();
+        let (other_secret, _) = server_keypair();
+        let packet = encrypt(&server_public, b"some handshake body").unwrap();
+        assert!(decrypt(&other_secret, &packet).is_err());
+    }
+}
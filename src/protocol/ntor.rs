@@ -0,0 +1,297 @@
+/*
+ * ntor-style key agreement (as used by the obfs4/ScrambleSuit
+ * pluggable transports), to give each session forward secrecy. NTOR1
+ * and NTOR2 use their own framing (below) and carry no AUTH line;
+ * they are not wrapped in the AUTH-framed HANDSHAKE/CONNECT packets
+ * of `protocol::protocol`. Where `hmac_sha256` derives
+ * everything from one static pre-shared password -- so a leaked
+ * password retroactively breaks every recorded session -- the session
+ * key here depends on a fresh ephemeral x25519 keypair per handshake.
+ * The password still authenticates the exchange (it plays the role of
+ * Tor's node identity key), but a captured password alone cannot
+ * decrypt previously recorded traffic.
+ *
+ * > Client -> Server: NTOR1 [client ephemeral pubkey X] [padding]
+ * > Server -> Client: NTOR2 [server ephemeral pubkey Y] [padding] [auth tag]
+ *
+ * session_key = HKDF-SHA256(EXP(X,y) || EXP(X,b) || node_id || X || Y)
+ * auth_tag    = HMAC_SHA256(passwd, node_id || X || Y || pad_len || pad)
+ *
+ * where b is the server's long-term x25519 static key and EXP(.,.) is
+ * x25519 Diffie-Hellman. Both EXP(X,y) and EXP(X,b) feed the KDF, so
+ * either the ephemeral or the static key alone is not enough to
+ * derive the session key.
+ *
+ * Like the HANDSHAKE/CONNECT packets in `protocol::protocol`, both
+ * NTOR1 and NTOR2 carry a random padding block and a 2-byte
+ * big-endian length prefix, so this exchange doesn't stand out as a
+ * pair of fixed-byte-length messages to a passive DPI box. The
+ * reply's padding is covered by its auth tag so it can't be stripped
+ * or tampered with; the request carries no secret yet to authenticate
+ * its own padding against, so it's left uncovered -- tampering with it
+ * only breaks framing, never the session key.
+ */
+use errors::*;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::Rng;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+
+pub const SESSION_KEY_LEN: usize = 32;
+const AUTH_TAG_LEN: usize = 32;
+const PUBKEY_LEN: usize = 32;
+const PAD_LEN_FIELD_LEN: usize = 2;
+const PAD_MIN_LEN: usize = 100;
+const PAD_MAX_LEN: usize = 300;
+
+/* Kept client-side between `ntor_handshake_build` and
+ * `ntor_handshake_finish`; never goes on the wire.
+ *
+ * The client's ephemeral keypair is held as a `StaticSecret` rather
+ * than an `EphemeralSecret`: `ntor_handshake_finish` needs to run
+ * `diffie_hellman` against it twice (once with `Y`, once with the
+ * server's static public key `B`, matching the server's `EXP(X,y)`
+ * and `EXP(X,b)`), and `EphemeralSecret::diffie_hellman` consumes `self`,
+ * so a second call would move an already-moved value. `StaticSecret`
+ * borrows `&self` and can be reused. */
+pub struct NtorClientState {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+fn rand_padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(PAD_MIN_LEN, PAD_MAX_LEN + 1);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn push_u16_be(buf: &mut Vec<u8>, n: u16) {
+    buf.push((n >> 8) as u8);
+    buf.push((n & 0xff) as u8);
+}
+
+fn read_u16_be(bytes: &[u8]) -> usize {
+    ((bytes[0] as usize) << 8) | (bytes[1] as usize)
+}
+
+/* Prefix `body` with its own 2-byte big-endian length, so a reader on
+ * a stream codec can frame it without knowing the padding length
+ * ahead of time. */
+fn frame(body: Vec<u8>) -> Result<Vec<u8>> {
+    if body.len() > u16::max_value() as usize {
+        return Err("ntor packet too large to frame".into());
+    }
+    let len = body.len() as u16;
+
+    let mut framed = Vec::with_capacity(2 + body.len());
+    push_u16_be(&mut framed, len);
+    framed.extend_from_slice(&body);
+    Ok(framed)
+}
+
+fn unframe(framed: &[u8]) -> Result<&[u8]> {
+    if framed.len() < 2 {
+        return Err("ntor packet too short".into());
+    }
+    let declared_len = read_u16_be(&framed[0..2]);
+    let body = &framed[2..];
+    if body.len() < declared_len {
+        return Err("Incomplete ntor packet".into());
+    }
+    Ok(&body[..declared_len])
+}
+
+/* Client side: generate a fresh ephemeral x25519 keypair and the NTOR1
+ * request packet carrying its public share plus random padding. */
+pub fn ntor_handshake_build() -> Result<(NtorClientState, Vec<u8>)> {
+    let secret = StaticSecret::new(&mut OsRng);
+    let public = PublicKey::from(&secret);
+    let pad = rand_padding();
+
+    let mut body = b"NTOR1 ".to_vec();
+    body.extend_from_slice(public.as_bytes());
+    push_u16_be(&mut body, pad.len() as u16);
+    body.extend_from_slice(&pad);
+
+    frame(body).map(|packet| (NtorClientState { secret, public }, packet))
+}
+
+/* Server side: parse a NTOR1 request, complete the exchange and
+ * return the NTOR2 reply packet together with the session key for
+ * the data path to consume. */
+pub fn ntor_handshake_parse(
+    passwd: &str,
+    node_id: &str,
+    server_secret: &StaticSecret,
+    packet: &[u8],
+) -> Result<(Vec<u8>, [u8; SESSION_KEY_LEN])> {
+    let body = unframe(packet)?;
+
+    if body.len() < 6 + PUBKEY_LEN + PAD_LEN_FIELD_LEN || &body[0..6] != b"NTOR1 " {
+        return Err("Not a ntor handshake request".into());
+    }
+    let client_public = read_pubkey(&body[6..6 + PUBKEY_LEN])?;
+
+    let pad_len_field = 6 + PUBKEY_LEN;
+    let pad_len = read_u16_be(&body[pad_len_field..pad_len_field + PAD_LEN_FIELD_LEN]);
+    if body.len() != pad_len_field + PAD_LEN_FIELD_LEN + pad_len {
+        return Err("Illegal ntor handshake request padding".into());
+    }
+
+    let server_ephemeral_secret = EphemeralSecret::new(&mut OsRng);
+    let server_ephemeral_public = PublicKey::from(&server_ephemeral_secret);
+
+    let exp_xy = server_ephemeral_secret.diffie_hellman(&client_public);
+    let exp_xb = server_secret.diffie_hellman(&client_public);
+
+    let session_key = derive_session_key(node_id, &client_public, &server_ephemeral_public, &exp_xy, &exp_xb)?;
+
+    let pad = rand_padding();
+    let tag = auth_tag(passwd, node_id, &client_public, &server_ephemeral_public, &pad)?;
+
+    let mut reply = b"NTOR2 ".to_vec();
+    reply.extend_from_slice(server_ephemeral_public.as_bytes());
+    push_u16_be(&mut reply, pad.len() as u16);
+    reply.extend_from_slice(&pad);
+    reply.extend_from_slice(&tag);
+
+    Ok((frame(reply)?, session_key))
+}
+
+/* Client side: verify a NTOR2 reply against the password-derived
+ * auth tag and derive the same session key the server did. */
+pub fn ntor_handshake_finish(
+    passwd: &str,
+    node_id: &str,
+    client: NtorClientState,
+    server_identity: &PublicKey,
+    reply: &[u8],
+) -> Result<[u8; SESSION_KEY_LEN]> {
+    let body = unframe(reply)?;
+
+    if body.len() < 6 + PUBKEY_LEN + PAD_LEN_FIELD_LEN + AUTH_TAG_LEN || &body[0..6] != b"NTOR2 " {
+        return Err("Not a ntor handshake reply".into());
+    }
+    let server_ephemeral_public = read_pubkey(&body[6..6 + PUBKEY_LEN])?;
+
+    let pad_len_field = 6 + PUBKEY_LEN;
+    let pad_len = read_u16_be(&body[pad_len_field..pad_len_field + PAD_LEN_FIELD_LEN]);
+    let pad_start = pad_len_field + PAD_LEN_FIELD_LEN;
+    if body.len() != pad_start + pad_len + AUTH_TAG_LEN {
+        return Err("Illegal ntor handshake reply padding".into());
+    }
+    let pad = &body[pad_start..pad_start + pad_len];
+    let tag = &body[pad_start + pad_len..];
+
+    let expected_tag = auth_tag(passwd, node_id, &client.public, &server_ephemeral_public, pad)?;
+    if expected_tag.as_slice().ct_eq(tag).unwrap_u8() != 1 {
+        return Err("ntor handshake authentication failed".into());
+    }
+
+    let exp_xy = client.secret.diffie_hellman(&server_ephemeral_public);
+    let exp_xb = client.secret.diffie_hellman(server_identity);
+
+    derive_session_key(node_id, &client.public, &server_ephemeral_public, &exp_xy, &exp_xb)
+}
+
+fn read_pubkey(bytes: &[u8]) -> Result<PublicKey> {
+    if bytes.len() != PUBKEY_LEN {
+        return Err("Illegal x25519 public key".into());
+    }
+    let mut buf = [0u8; PUBKEY_LEN];
+    buf.copy_from_slice(bytes);
+    Ok(PublicKey::from(buf))
+}
+
+fn derive_session_key(
+    node_id: &str,
+    client_public: &PublicKey,
+    server_public: &PublicKey,
+    exp_xy: &SharedSecret,
+    exp_xb: &SharedSecret,
+) -> Result<[u8; SESSION_KEY_LEN]> {
+    let mut ikm = Vec::with_capacity(2 * PUBKEY_LEN + node_id.len() + 2 * PUBKEY_LEN);
+    ikm.extend_from_slice(exp_xy.as_bytes());
+    ikm.extend_from_slice(exp_xb.as_bytes());
+    ikm.extend_from_slice(node_id.as_bytes());
+    ikm.extend_from_slice(client_public.as_bytes());
+    ikm.extend_from_slice(server_public.as_bytes());
+
+    let mut session_key = [0u8; SESSION_KEY_LEN];
+    Hkdf::<Sha256>::new(None, &ikm)
+        .expand(b"tws-ntor-session", &mut session_key)
+        .map_err(|_| "ntor session key derivation failed".into())
+        .map(|_| session_key)
+}
+
+fn auth_tag(
+    passwd: &str,
+    node_id: &str,
+    client_public: &PublicKey,
+    server_public: &PublicKey,
+    pad: &[u8],
+) -> Result<Vec<u8>> {
+    Hmac::<Sha256>::new(passwd.as_bytes())
+        .map_err(|_| "ntor auth tag setup failed".into())
+        .map(|mut mac| {
+            mac.input(node_id.as_bytes());
+            mac.input(client_public.as_bytes());
+            mac.input(server_public.as_bytes());
+            let mut pad_len = Vec::with_capacity(PAD_LEN_FIELD_LEN);
+            push_u16_be(&mut pad_len, pad.len() as u16);
+            mac.input(&pad_len);
+            mac.input(pad);
+            mac.result().code().as_slice().to_vec()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ntor_handshake_round_trip_derives_matching_session_keys() {
+        let server_secret = StaticSecret::new(&mut OsRng);
+        let server_public = PublicKey::from(&server_secret);
+
+        let (client_state, request) = ntor_handshake_build().unwrap();
+        let (reply, server_session_key) = ntor_handshake_parse("shhh", "node-1", &server_secret, &request).unwrap();
+        let client_session_key = ntor_handshake_finish("shhh", "node-1", client_state, &server_public, &reply).unwrap();
+
+        assert_eq!(server_session_key, client_session_key);
+    }
+
+    #[test]
+    fn ntor_handshake_finish_rejects_wrong_password() {
+        let server_secret = StaticSecret::new(&mut OsRng);
+        let server_public = PublicKey::from(&server_secret);
+
+        let (client_state, request) = ntor_handshake_build().unwrap();
+        let (reply, _) = ntor_handshake_parse("shhh", "node-1", &server_secret, &request).unwrap();
+
+        assert!(ntor_handshake_finish("wrong", "node-1", client_state, &server_public, &reply).is_err());
+    }
+
+    #[test]
+    fn ntor_handshake_finish_rejects_tampered_reply_padding() {
+        let server_secret = StaticSecret::new(&mut OsRng);
+        let server_public = PublicKey::from(&server_secret);
+
+        let (client_state, request) = ntor_handshake_build().unwrap();
+        let (mut reply, _) = ntor_handshake_parse("shhh", "node-1", &server_secret, &request).unwrap();
+        let pad_start = 2 + 6 + PUBKEY_LEN + PAD_LEN_FIELD_LEN;
+        reply[pad_start] ^= 0xff;
+
+        assert!(ntor_handshake_finish("shhh", "node-1", client_state, &server_public, &reply).is_err());
+    }
+
+    #[test]
+    fn ntor_handshake_request_padding_varies() {
+        let (_, request_a) = ntor_handshake_build().unwrap();
+        let (_, request_b) = ntor_handshake_build().unwrap();
+        assert_ne!(request_a[2 + 6 + PUBKEY_LEN..], request_b[2 + 6 + PUBKEY_LEN..]);
+    }
+}
@@ -2,88 +2,202 @@
  * This submodule contains implementation of basic
  * elements of the TWS protocol.
  * TODO: Better documentation
- * TODO: Randomize packet length
- *      or try to add random meaningless
- *      packets during the session.
  */
 use errors::*;
-use base64::encode;
+use base64::{decode, encode};
 use hmac::{Hmac, Mac};
+use protocol::ecies;
+use rand::Rng;
 use sha2::Sha256;
+use std::collections::{HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::str;
+use subtle::ConstantTimeEq;
 use protocol::util;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/* Handshake/CONNECT packets are padded with a random number of bytes
+ * so that two packets to the same target rarely share a byte length,
+ * frustrating passive length-based DPI classifiers. */
+const PAD_MIN_LEN: usize = 100;
+const PAD_MAX_LEN: usize = 300;
 
 /*
  * HMAC_SHA256 authentication wrapper
  * This is used for HANDSHAKE and CONNECT packets
  */
 pub fn hmac_sha256(passwd: &str, data: &str) -> Result<String> {
+    hmac_sha256_bytes(passwd, data.as_bytes()).map(|mac| encode(&mac))
+}
+
+fn hmac_sha256_bytes(passwd: &str, data: &[u8]) -> Result<Vec<u8>> {
     Hmac::<Sha256>::new(passwd.as_bytes())
         .and_then(|mut mac| {
-            mac.input(data.as_bytes());
-            Ok(encode(mac.result().code().as_slice()))
+            mac.input(data);
+            Ok(mac.result().code().as_slice().to_vec())
         })
         .map_err(|_| "HMAC_SHA256 failed".into())
 }
 
-fn build_authenticated_packet(passwd: &str, msg: &str) -> Result<String> {
-    hmac_sha256(passwd, msg)
-        .and_then(|auth| Ok(format!("AUTH {}\n{}", auth, msg)))
+fn rand_padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(PAD_MIN_LEN, PAD_MAX_LEN + 1);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/*
+ * An authenticated packet on the wire looks like:
+ *
+ * > [2-byte big-endian length of everything below]
+ * > AUTH [authentication code]
+ * > PAD [padding length]
+ * > ...msg...
+ * > [padding length] random bytes
+ *
+ * The authentication code covers the PAD line, the message and the
+ * padding bytes, so the padding can't be stripped or tampered with
+ * without invalidating the MAC. The padding itself carries no meaning;
+ * it only exists to decorrelate the packet length from the message.
+ */
+fn build_authenticated_packet(passwd: &str, msg: &str) -> Result<Vec<u8>> {
+    _build_authenticated_packet(passwd, msg, &rand_padding())
 }
 
-fn parse_authenticated_packet(passwd: &str, packet: &[u8]) -> Result<Vec<String>> {
-    if packet[0..4] != "AUTH".as_bytes()[0..4] {
+fn _build_authenticated_packet(passwd: &str, msg: &str, pad: &[u8]) -> Result<Vec<u8>> {
+    let body = format!("PAD {}\n{}", pad.len(), msg);
+    let mut mac_input = body.clone().into_bytes();
+    mac_input.extend_from_slice(pad);
+
+    hmac_sha256_bytes(passwd, &mac_input)
+        .and_then(|mac| {
+            let mut packet = format!("AUTH {}\n", encode(&mac)).into_bytes();
+            packet.extend_from_slice(body.as_bytes());
+            packet.extend_from_slice(pad);
+
+            if packet.len() > u16::max_value() as usize {
+                return Err("Packet too large to frame".into());
+            }
+            let len = packet.len() as u16;
+
+            let mut framed = Vec::with_capacity(2 + packet.len());
+            framed.push((len >> 8) as u8);
+            framed.push((len & 0xff) as u8);
+            framed.extend_from_slice(&packet);
+            Ok(framed)
+        })
+}
+
+fn parse_authenticated_packet(passwd: &str, framed: &[u8]) -> Result<Vec<String>> {
+    if framed.len() < 2 {
+        return Err("Packet too short".into());
+    }
+    let declared_len = ((framed[0] as usize) << 8) | (framed[1] as usize);
+    let packet = &framed[2..];
+    if packet.len() < declared_len {
+        return Err("Incomplete packet".into());
+    }
+    let packet = &packet[..declared_len];
+
+    if packet.len() < 4 || &packet[0..4] != "AUTH".as_bytes() {
         return Err("Not a proper authenticated packet.".into());
     }
 
-    str::from_utf8(packet)
-        .map_err(|_| "Illegal packet".into())
-        .and_then(|packet_str| {
-            let lines = packet_str.lines()
-                .map(|s| String::from(s))
-                .collect::<Vec<String>>();
+    let auth_end = packet.iter().position(|&b| b == b'\n')
+        .ok_or("Illegal packet")?;
+    let auth_line = str::from_utf8(&packet[..auth_end]).map_err(|_| "Illegal packet")?;
+    if !auth_line.starts_with("AUTH ") {
+        return Err("Illegal packet".into());
+    }
+    let received_mac = decode(&auth_line[5..]).map_err(|_| "Illegal packet")?;
+    let rest = &packet[auth_end + 1..];
 
-            hmac_sha256(passwd, &lines[1..].join("\n"))
-                .and_then(|auth| Ok((lines, auth)))
-        })
-        .and_then(|(lines, auth)| {
-            if lines[0] == format!("AUTH {}", auth) {
-                Ok(lines[1..].to_vec())
+    hmac_sha256_bytes(passwd, rest)
+        .and_then(|expected_mac| {
+            /* Constant-time comparison: a variable-time `==` here would
+             * leak how many leading bytes of the MAC an attacker
+             * guessed correctly through response timing. */
+            if expected_mac.ct_eq(&received_mac).unwrap_u8() == 1 {
+                Ok(())
             } else {
                 Err("Illegal packet".into())
             }
         })
+        .and_then(|_| strip_padding(rest))
+}
+
+fn strip_padding(rest: &[u8]) -> Result<Vec<String>> {
+    let pad_end = rest.iter().position(|&b| b == b'\n')
+        .ok_or("Illegal packet")?;
+    let pad_line = str::from_utf8(&rest[..pad_end]).map_err(|_| "Illegal packet")?;
+    if !pad_line.starts_with("PAD ") {
+        return Err("Illegal packet".into());
+    }
+    let pad_len: usize = pad_line[4..].parse().chain_err(|| "Illegal padding length")?;
+
+    let msg_and_pad = &rest[pad_end + 1..];
+    if msg_and_pad.len() < pad_len {
+        return Err("Illegal packet".into());
+    }
+    let msg = &msg_and_pad[..msg_and_pad.len() - pad_len];
+
+    str::from_utf8(msg)
+        .map_err(|_| "Illegal packet".into())
+        .map(|packet_str| packet_str.lines().map(String::from).collect())
 }
 
 /*
  * Handshake packet
- * 
+ *
  * > AUTH [authentication code]
+ * > PAD [padding length]
  * > NOW [current timestamp (UTC)]
  * > TARGET [targetHost]:[targetPort]
- * 
+ * > [padding bytes]
+ *
  * [authentication code] is the HMAC_SHA256 value
  * based on the pre-shared password and
- * the full message without the AUTH line.
+ * the full message (PAD line, NOW/TARGET lines and padding) without
+ * the AUTH line.
+ *
+ * When `HandshakeSecurity::Ecies` is used, the packet above becomes
+ * the plaintext of an ECIES envelope (see `protocol::ecies`) addressed
+ * to the server's long-term x25519 public key, so the TARGET line is
+ * no longer visible to an on-path observer. `SharedPasswordOnly`
+ * preserves the original authenticate-but-don't-encrypt behaviour for
+ * deployments that haven't configured a server key yet.
  */
-pub fn handshake_build(passwd: &str, target: SocketAddr) -> Result<String> {
-    _handshake_build(passwd, util::time_ms(), target)
+pub enum HandshakeSecurity {
+    SharedPasswordOnly,
+    Ecies(PublicKey),
 }
 
-fn _handshake_build(passwd: &str, time: i64, target: SocketAddr) -> Result<String> {
-    build_authenticated_packet(
+pub fn handshake_build(passwd: &str, security: &HandshakeSecurity, target: SocketAddr) -> Result<Vec<u8>> {
+    _handshake_build(passwd, security, util::time_ms(), target, &rand_padding())
+}
+
+fn _handshake_build(passwd: &str, security: &HandshakeSecurity, time: i64, target: SocketAddr, pad: &[u8]) -> Result<Vec<u8>> {
+    _build_authenticated_packet(
         passwd,
-        &format!("NOW {}\nTARGET {}", time, util::addr_to_str(target))
+        &format!("NOW {}\nTARGET {}", time, util::addr_to_str(target)),
+        pad
     )
+        .and_then(|packet| match *security {
+            HandshakeSecurity::SharedPasswordOnly => Ok(packet),
+            HandshakeSecurity::Ecies(ref server_public) => ecies::encrypt(server_public, &packet),
+        })
 }
 
-pub fn handshake_parse(passwd: &str, packet: &[u8]) -> Result<SocketAddr> {
-    _handshake_parse(passwd, util::time_ms(), packet)
+pub fn handshake_parse(passwd: &str, server_secret: Option<&StaticSecret>, packet: &[u8]) -> Result<SocketAddr> {
+    _handshake_parse(passwd, server_secret, util::time_ms(), packet)
 }
 
-fn _handshake_parse(passwd: &str, time: i64, packet: &[u8]) -> Result<SocketAddr> {
-    parse_authenticated_packet(passwd, packet)
+fn _handshake_parse(passwd: &str, server_secret: Option<&StaticSecret>, time: i64, packet: &[u8]) -> Result<SocketAddr> {
+    let packet = match server_secret {
+        Some(secret) => ecies::decrypt(secret, packet)?,
+        None => packet.to_vec(),
+    };
+
+    parse_authenticated_packet(passwd, &packet)
         .and_then(|lines| {
             if lines.len() < 2 {
                 return Err("Not a handshake packet".into());
@@ -109,40 +223,113 @@ fn _handshake_parse(passwd: &str, time: i64, packet: &[u8]) -> Result<SocketAddr
 
 /*
  * Connect packet
- * 
+ *
  * > AUTH [authentication code]
+ * > PAD [padding length]
+ * > NOW [current timestamp (UTC)]
  * > NEW CONNECTION [conn id]
- * 
+ * > [padding bytes]
+ *
  * [conn id] should be a random 6-char string
  * generated by the client side.
- * TODO: Should we make authentication for this
- *  kind of packets more strict? i.e. include time
+ *
+ * The same 5s freshness window as the handshake applies to NOW (a NOW
+ * more than 5s behind the local clock is rejected; one ahead of it is
+ * not), and
+ * `connect_parse` rejects a [conn id] it has already seen within a
+ * `ReplayCache`, so a captured CONNECT packet can't be replayed to
+ * open a duplicate connection while it's still within the window.
  */
-fn connect_build(passwd: &str) -> Result<(String, String)> {
+const CONN_ID_REPLAY_CACHE_CAPACITY: usize = 4096;
+
+/// Bounded, FIFO-evicted cache of recently seen `conn_id`s, used by
+/// `connect_parse` to reject replayed CONNECT packets. Capacity only
+/// needs to cover the 5s freshness window's worth of traffic, since
+/// anything older than that is already rejected on the timestamp
+/// check.
+pub struct ReplayCache {
+    capacity: usize,
+    order: VecDeque<String>,
+    seen: HashSet<String>,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> ReplayCache {
+        ReplayCache {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Records `conn_id` as seen. Returns `true` the first time a
+    /// given `conn_id` is recorded, `false` on every subsequent replay.
+    fn record(&mut self, conn_id: &str) -> bool {
+        if !self.seen.insert(conn_id.to_string()) {
+            return false;
+        }
+
+        self.order.push_back(conn_id.to_string());
+        if self.order.len() > self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.seen.remove(&evicted);
+            }
+        }
+        true
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> ReplayCache {
+        ReplayCache::new(CONN_ID_REPLAY_CACHE_CAPACITY)
+    }
+}
+
+fn connect_build(passwd: &str) -> Result<(String, Vec<u8>)> {
     let conn_id = util::rand_str(6);
-    _connect_build(passwd, &conn_id)
+    _connect_build(passwd, util::time_ms(), &conn_id, &rand_padding())
         .and_then(|packet| Ok((conn_id, packet)))
 }
 
-fn _connect_build(passwd: &str, conn_id: &str) -> Result<String> {
-    build_authenticated_packet(
+fn _connect_build(passwd: &str, time: i64, conn_id: &str, pad: &[u8]) -> Result<Vec<u8>> {
+    _build_authenticated_packet(
         passwd,
-        &format!("NEW CONNECTION {}", conn_id)
+        &format!("NOW {}\nNEW CONNECTION {}", time, conn_id),
+        pad
     )
 }
 
-fn connect_parse(passwd: &str, packet: &[u8]) -> Result<String> {
+pub fn connect_parse(passwd: &str, cache: &mut ReplayCache, packet: &[u8]) -> Result<String> {
+    _connect_parse(passwd, util::time_ms(), cache, packet)
+}
+
+fn _connect_parse(passwd: &str, time: i64, cache: &mut ReplayCache, packet: &[u8]) -> Result<String> {
     parse_authenticated_packet(passwd, packet)
         .and_then(|lines| {
-            if lines.len() < 1 {
+            if lines.len() < 2 {
                 return Err("Not a Connect packet".into());
             }
-
-            if !(lines[0].starts_with("NEW CONNECTION ") && lines[0].len() == 21) {
+            if !(lines[0].starts_with("NOW ") && lines[0].len() > 4) {
+                return Err("Not a Connect packet".into());
+            }
+            if !(lines[1].starts_with("NEW CONNECTION ") && lines[1].len() == 21) {
                 return Err("Not a Connect packet".into());
             }
+            lines[0][4..].parse::<i64>()
+                .chain_err(|| "Illegal Connect packet")
+                .and_then(|packet_time| Ok((packet_time, lines)))
+        })
+        .and_then(|(packet_time, lines)| {
+            if time - packet_time > 5 * 1000 {
+                return Err("Connect packet timed out".into());
+            }
 
-            Ok(String::from(&lines[0][15..]))
+            let conn_id = String::from(&lines[1][15..]);
+            if cache.record(&conn_id) {
+                Ok(conn_id)
+            } else {
+                Err("Replayed Connect packet".into())
+            }
         })
 }
 
@@ -161,60 +348,106 @@ mod tests {
     }
 
     #[test]
-    fn handshake_build_1() {
-        assert_eq!(
-            "AUTH s4V0i9Lwlm6eve7JftwGEgKN20mgtbSW3uacxIuh0Fo=\nNOW 1517476212983\nTARGET 192.168.1.1:443",
-            _handshake_build("bscever", 1517476212983, util::str_to_addr("192.168.1.1:443").unwrap()).unwrap()
-        );
-    }
+    fn handshake_build_has_length_prefix_and_pad_line() {
+        let pad = vec![7u8; 150];
+        let packet = _handshake_build("bscever", &HandshakeSecurity::SharedPasswordOnly, 1517476212983, util::str_to_addr("192.168.1.1:443").unwrap(), &pad).unwrap();
 
-    #[test]
-    fn handshake_build_2() {
-        assert_eq!(
-            "AUTH wrhyAKqrQKln+Jj9rSlpiDC1+/gw8vi5o6yIMnB5oOM=\nNOW 1517476367329\nTARGET 8.8.4.4:62311",
-            _handshake_build("0o534hn045", 1517476367329, util::str_to_addr("8.8.4.4:62311").unwrap()).unwrap()
-        );
+        let declared_len = ((packet[0] as usize) << 8) | (packet[1] as usize);
+        assert_eq!(declared_len, packet.len() - 2);
+
+        let body = str::from_utf8(&packet[2..packet.len() - pad.len()]).unwrap();
+        assert!(body.starts_with("AUTH "));
+        assert!(body.contains("\nPAD 150\n"));
+        assert!(body.contains("NOW 1517476212983\nTARGET 192.168.1.1:443"));
+        assert_eq!(&packet[packet.len() - pad.len()..], pad.as_slice());
     }
 
     #[test]
     fn handshake_build_parse_1() {
         let t = util::time_ms();
-        let handshake = _handshake_build("evbie", t, util::str_to_addr("233.233.233.233:456").unwrap()).unwrap();
-        assert_eq!("233.233.233.233:456", util::addr_to_str(_handshake_parse("evbie", t, handshake.as_bytes()).unwrap()));
+        let handshake = _handshake_build("evbie", &HandshakeSecurity::SharedPasswordOnly, t, util::str_to_addr("233.233.233.233:456").unwrap(), &rand_padding()).unwrap();
+        assert_eq!("233.233.233.233:456", util::addr_to_str(_handshake_parse("evbie", None, t, &handshake).unwrap()));
     }
 
     #[test]
     fn handshake_build_parse_2() {
         let t = util::time_ms();
-        let handshake = _handshake_build("43g,poe3w", t, util::str_to_addr("fe80::dead:beef:2333:8080").unwrap()).unwrap();
-        assert_eq!("fe80::dead:beef:2333:8080", util::addr_to_str(_handshake_parse("43g,poe3w", t, handshake.as_bytes()).unwrap()));
+        let handshake = _handshake_build("43g,poe3w", &HandshakeSecurity::SharedPasswordOnly, t, util::str_to_addr("fe80::dead:beef:2333:8080").unwrap(), &rand_padding()).unwrap();
+        assert_eq!("fe80::dead:beef:2333:8080", util::addr_to_str(_handshake_parse("43g,poe3w", None, t, &handshake).unwrap()));
     }
 
     #[test]
-    fn connect_build_1() {
-        assert_eq!(
-            "AUTH +cdQQVGtyqj7KxTS5mPEwvpRGhRuctCM3pa9GsTYGZA=\nNEW CONNECTION XnjEa2",
-            _connect_build("eeovgrg", "XnjEa2").unwrap()
-        );
+    fn handshake_parse_rejects_tampered_padding() {
+        let t = util::time_ms();
+        let mut handshake = _handshake_build("evbie", &HandshakeSecurity::SharedPasswordOnly, t, util::str_to_addr("233.233.233.233:456").unwrap(), &rand_padding()).unwrap();
+        let last = handshake.len() - 1;
+        handshake[last] ^= 0xff;
+        assert!(_handshake_parse("evbie", None, t, &handshake).is_err());
     }
 
     #[test]
-    fn connect_parse_1() {
+    fn handshake_build_parse_ecies_hides_and_recovers_target() {
+        let server_secret = StaticSecret::new(&mut rand::rngs::OsRng);
+        let server_public = PublicKey::from(&server_secret);
+        let t = util::time_ms();
+
+        let handshake = _handshake_build(
+            "evbie",
+            &HandshakeSecurity::Ecies(server_public),
+            t,
+            util::str_to_addr("233.233.233.233:456").unwrap(),
+            &rand_padding()
+        ).unwrap();
+
+        assert!(!handshake.windows(7).any(|w| w == b"TARGET "));
         assert_eq!(
-            "37keeU",
-            connect_parse("fneo0ivb", b"AUTH +l0yOYsTR0oqvj7//0iO24WjmdxRKNmMwVhXZpVLwvY=\nNEW CONNECTION 37keeU").unwrap()
+            "233.233.233.233:456",
+            util::addr_to_str(_handshake_parse("evbie", Some(&server_secret), t, &handshake).unwrap())
         );
     }
 
     #[test]
-    #[should_panic]
-    fn connect_parse_fail_1() {
-        connect_parse("fneo0ib", b"AUTH +l0yOYsTR0oqvj7//0iO24WjmdxRKNmMwVhXZpVLwvY=\nNEW CONNECTION 37keeU").unwrap();
+    fn connect_build_parse_1() {
+        let t = util::time_ms();
+        let packet = _connect_build("eeovgrg", t, "XnjEa2", &rand_padding()).unwrap();
+        let mut cache = ReplayCache::default();
+        assert_eq!("XnjEa2", _connect_parse("eeovgrg", t, &mut cache, &packet).unwrap());
     }
 
     #[test]
     #[should_panic]
-    fn connect_parse_fail_2() {
-        connect_parse("fneo0ivb", b"AUTH +l0yOYsTR0oqvj77/0iO24WjmdxRKNmMwVhXZpVLwvY=\nNEW CONNECTION 37keeU").unwrap();
+    fn connect_parse_fail_wrong_passwd() {
+        let t = util::time_ms();
+        let packet = _connect_build("fneo0ivb", t, "37keeU", &rand_padding()).unwrap();
+        let mut cache = ReplayCache::default();
+        _connect_parse("fneo0ib", t, &mut cache, &packet).unwrap();
+    }
+
+    #[test]
+    fn connect_build_packets_vary() {
+        let (_, a) = connect_build("eeovgrg").unwrap();
+        let (_, b) = connect_build("eeovgrg").unwrap();
+        /* `rand_padding` draws uniformly from 201 possible lengths, so
+         * asserting on length alone would collide ~0.5% of the time.
+         * Compare the packet bytes instead, which also differ in the
+         * random conn id, the padding bytes and the MAC over them. */
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn connect_parse_rejects_stale_timestamp() {
+        let t = util::time_ms();
+        let packet = _connect_build("eeovgrg", t - 6 * 1000, "StalE1", &rand_padding()).unwrap();
+        let mut cache = ReplayCache::default();
+        assert!(_connect_parse("eeovgrg", t, &mut cache, &packet).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn connect_parse_rejects_replayed_conn_id() {
+        let t = util::time_ms();
+        let packet = _connect_build("eeovgrg", t, "Replay", &rand_padding()).unwrap();
+        let mut cache = ReplayCache::default();
+        assert_eq!("Replay", _connect_parse("eeovgrg", t, &mut cache, &packet).unwrap());
+        assert!(_connect_parse("eeovgrg", t, &mut cache, &packet).is_err());
+    }
+}